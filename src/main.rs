@@ -1,13 +1,17 @@
+use aes::cipher::{block_padding::Pkcs7, BlockDecryptMut, KeyIvInit};
 use anyhow::{Error, Result};
+use clap::Parser;
 use futures::future::{join_all, BoxFuture, FutureExt, TryFutureExt};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use m3u8_rs::{
     parse_playlist,
-    playlist::{MasterPlaylist, MediaPlaylist, Playlist},
+    playlist::{MasterPlaylist, MediaPlaylist, Playlist, VariantStream},
 };
 use once_cell::sync::Lazy;
 use reqwest::{Client, Response};
+use serde::{Deserialize, Serialize};
 use std::{
-    env,
+    collections::HashMap,
     io::SeekFrom,
     mem::take,
     path::{Path, PathBuf},
@@ -15,12 +19,237 @@ use std::{
     time::Duration,
 };
 use tokio::{
-    fs::{create_dir_all, write, File, OpenOptions},
-    io::AsyncWriteExt,
-    sync::Semaphore,
+    fs::{create_dir_all, metadata, read, remove_dir_all, rename, write, File, OpenOptions},
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::{Mutex, Semaphore},
 };
 use url::Url;
 
+type Aes128CbcDec = cbc::Decryptor<aes::Aes128>;
+
+/// An `EXT-X-KEY:METHOD=AES-128` key resolved against the playlist's base URL, plus the IV to
+/// use for the segment it applies to.
+#[derive(Debug, Clone)]
+struct SegmentKey {
+    key_url: Url,
+    iv: [u8; 16],
+}
+
+fn decode_hex_iv(hex: &str) -> Option<[u8; 16]> {
+    let hex = hex
+        .strip_prefix("0x")
+        .or_else(|| hex.strip_prefix("0X"))
+        .unwrap_or(hex);
+    if hex.len() != 32 {
+        return None;
+    }
+    let mut iv = [0u8; 16];
+    for (i, byte) in iv.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+    }
+    Some(iv)
+}
+
+fn sequence_iv(media_sequence: u64) -> [u8; 16] {
+    let mut iv = [0u8; 16];
+    iv[8..].copy_from_slice(&media_sequence.to_be_bytes());
+    iv
+}
+
+async fn fetch_decryption_key(
+    cache: &Mutex<HashMap<String, Arc<[u8; 16]>>>,
+    key_url: &Url,
+) -> Result<Arc<[u8; 16]>> {
+    if let Some(key) = cache.lock().await.get(key_url.as_str()) {
+        return Ok(key.to_owned());
+    }
+
+    const RETRIES: u8 = 10;
+    let mut last_error: Option<Error> = None;
+    for retried in 0u8..RETRIES {
+        match HTTP_CLIENT
+            .get(key_url.as_str())
+            .send()
+            .and_then(|resp| resp.bytes())
+            .await
+        {
+            Ok(bytes) => {
+                let key: [u8; 16] = match bytes.as_ref().try_into() {
+                    Ok(key) => key,
+                    Err(_) => {
+                        return Err(Error::msg(format!(
+                            "AES-128 key at {} is not 16 bytes",
+                            key_url
+                        )))
+                    }
+                };
+                let key = Arc::new(key);
+                cache
+                    .lock()
+                    .await
+                    .insert(key_url.to_string(), key.to_owned());
+                return Ok(key);
+            }
+            Err(err) => {
+                eprintln!("HTTP Get Key Error ({} / {}): {}", retried, RETRIES, err);
+                last_error = Some(err.into());
+            }
+        }
+    }
+    Err(last_error.unwrap())
+}
+
+fn decrypt_aes128_cbc(ciphertext: &[u8], key: &[u8; 16], iv: &[u8; 16]) -> Result<Vec<u8>> {
+    Aes128CbcDec::new(key.into(), iv.into())
+        .decrypt_padded_vec_mut::<Pkcs7>(ciphertext)
+        .map_err(|err| Error::msg(format!("AES-128 decryption failed: {}", err)))
+}
+
+/// Playlists with at most this many segments are concatenated in memory; larger ones are
+/// streamed straight to the output file to avoid buffering the whole video in RAM.
+const IN_MEMORY_SEGMENT_LIMIT: usize = 64;
+
+/// A single concatenated output, either buffered in memory or streamed to disk, depending on
+/// how many segments are being assembled.
+enum DualWriter {
+    Memory(Vec<u8>),
+    File(File),
+}
+
+impl DualWriter {
+    async fn new(output_path: &Path, segment_count: usize) -> Result<Self> {
+        if segment_count <= IN_MEMORY_SEGMENT_LIMIT {
+            Ok(DualWriter::Memory(Vec::new()))
+        } else {
+            Ok(DualWriter::File(File::create(output_path).await?))
+        }
+    }
+
+    async fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        match self {
+            DualWriter::Memory(buffer) => {
+                buffer.extend_from_slice(buf);
+                Ok(())
+            }
+            DualWriter::File(file) => Ok(file.write_all(buf).await?),
+        }
+    }
+
+    async fn finish(self, output_path: &Path) -> Result<()> {
+        match self {
+            DualWriter::Memory(buffer) => Ok(write(output_path, buffer).await?),
+            DualWriter::File(mut file) => Ok(file.flush().await?),
+        }
+    }
+}
+
+async fn concatenate_segments(playlist: &MediaPlaylist, output_path: &Path) -> Result<()> {
+    let mut writer = DualWriter::new(output_path, playlist.segments.len()).await?;
+    for segment in playlist.segments.iter() {
+        writer.write_all(&read(&segment.uri).await?).await?;
+    }
+    writer.finish(output_path).await
+}
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// Tracks, per segment id, the source URL and final byte length once a segment has been
+/// fetched in full, so a re-run can tell a finished segment from a truncated one.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct DownloadManifest {
+    segments: HashMap<usize, SegmentRecord>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct SegmentRecord {
+    url: String,
+    length: u64,
+}
+
+async fn load_manifest(dir: &Path) -> DownloadManifest {
+    match read(dir.join(MANIFEST_FILE_NAME)).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_default(),
+        Err(_) => DownloadManifest::default(),
+    }
+}
+
+async fn save_manifest(dir: &Path, manifest: &DownloadManifest) -> Result<()> {
+    let bytes = serde_json::to_vec_pretty(manifest)?;
+    write(dir.join(MANIFEST_FILE_NAME), bytes).await?;
+    Ok(())
+}
+
+/// Download every segment of an HLS playlist and concatenate them into a single <name>.ts
+/// file, decrypting AES-128 segments along the way. Pass --keep-segments to keep the
+/// per-segment .ts files and a rewritten .m3u8 instead.
+#[derive(Parser, Debug)]
+#[command(author, version, about)]
+struct Args {
+    /// URL of the (master or media) m3u8 playlist to download
+    url: String,
+
+    /// Print each variant's bandwidth, resolution and codecs and exit without downloading
+    #[arg(long)]
+    list_variants: bool,
+
+    /// Keep the per-segment .ts files and the rewritten .m3u8 instead of concatenating
+    /// everything into a single <name>.ts
+    #[arg(long)]
+    keep_segments: bool,
+
+    /// Pick the highest-bandwidth variant (default)
+    #[arg(long, conflicts_with_all = ["lowest", "height", "max_bandwidth"])]
+    highest: bool,
+
+    /// Pick the lowest-bandwidth variant
+    #[arg(long, conflicts_with_all = ["highest", "height", "max_bandwidth"])]
+    lowest: bool,
+
+    /// Pick the variant whose RESOLUTION height is closest to this value
+    #[arg(long, conflicts_with_all = ["highest", "lowest", "max_bandwidth"])]
+    height: Option<u32>,
+
+    /// Pick the highest-bandwidth variant at or below this cap, in bits/sec
+    #[arg(long, conflicts_with_all = ["highest", "lowest", "height"])]
+    max_bandwidth: Option<u64>,
+}
+
+impl Args {
+    fn quality_preset(&self) -> QualityPreset {
+        if let Some(height) = self.height {
+            QualityPreset::Nearest { height }
+        } else if let Some(max_bandwidth) = self.max_bandwidth {
+            QualityPreset::MaxBandwidth(max_bandwidth)
+        } else if self.lowest {
+            QualityPreset::Lowest
+        } else {
+            QualityPreset::Highest
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+enum QualityPreset {
+    Highest,
+    Lowest,
+    Nearest { height: u32 },
+    MaxBandwidth(u64),
+}
+
+static SEGMENT_PROGRESS_STYLE: Lazy<ProgressStyle> = Lazy::new(|| {
+    ProgressStyle::with_template(
+        "{spinner:.green} [{bar:30.cyan/blue}] {bytes}/{total_bytes} ({bytes_per_sec}, {eta})",
+    )
+    .unwrap()
+    .progress_chars("#>-")
+});
+
+static TOTAL_PROGRESS_STYLE: Lazy<ProgressStyle> = Lazy::new(|| {
+    ProgressStyle::with_template("Segments [{bar:30.green/blue}] {pos}/{len}")
+        .unwrap()
+        .progress_chars("#>-")
+});
+
 static HTTP_CLIENT: Lazy<Client> = Lazy::new(|| {
     Client::builder()
         .connect_timeout(Duration::from_secs(5))
@@ -31,12 +260,17 @@ static HTTP_CLIENT: Lazy<Client> = Lazy::new(|| {
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let url =
-        Url::parse(&env::args().nth(1).expect("URL must be given")).expect("URL must be valid");
+    let args = Args::parse();
+    let url = Url::parse(&args.url).expect("URL must be valid");
+
+    if args.list_variants {
+        return list_variants(&url).await;
+    }
+
     let m3u8_filename = {
         let mut segment = url
             .path_segments()
-            .and_then(|segments| segments.last())
+            .and_then(|mut segments| segments.next_back())
             .expect("At least 1 path segment expected")
             .to_owned();
         if !segment.ends_with(".m3u8") {
@@ -44,28 +278,34 @@ async fn main() -> Result<()> {
         }
         segment
     };
-    let mut playlist = choose_media_playlist(vec![url]).await?;
+    let quality_preset = args.quality_preset();
+    let (mut playlist, segment_keys) = choose_media_playlist(vec![url], quality_preset).await?;
 
-    {
-        let ts_dir_name = {
-            let mut dir = PathBuf::new();
-            dir.push(m3u8_filename.to_owned() + ".ts");
-            dir
-        };
-        create_dir_all(&ts_dir_name).await?;
-        download_ts_and_replace(&ts_dir_name, &mut playlist).await?;
-    }
+    let ts_dir_name = {
+        let mut dir = PathBuf::new();
+        dir.push(m3u8_filename.to_owned() + ".ts");
+        dir
+    };
+    create_dir_all(&ts_dir_name).await?;
+    download_ts_and_replace(&ts_dir_name, &mut playlist, &segment_keys).await?;
 
-    {
+    if args.keep_segments {
         let mut m3u8_content = Vec::new();
         playlist.write_to(&mut m3u8_content).unwrap();
         write(&m3u8_filename, m3u8_content).await?;
+    } else {
+        let output_path = PathBuf::from(m3u8_filename.trim_end_matches(".m3u8").to_owned() + ".ts");
+        concatenate_segments(&playlist, &output_path).await?;
+        remove_dir_all(&ts_dir_name).await?;
     }
 
     Ok(())
 }
 
-fn choose_media_playlist(urls: Vec<Url>) -> BoxFuture<'static, Result<MediaPlaylist>> {
+fn choose_media_playlist(
+    urls: Vec<Url>,
+    quality_preset: QualityPreset,
+) -> BoxFuture<'static, Result<(MediaPlaylist, Vec<Option<SegmentKey>>)>> {
     async move {
         let mut last_error: Option<Error> = None;
         for url in urls.into_iter() {
@@ -78,14 +318,15 @@ fn choose_media_playlist(urls: Vec<Url>) -> BoxFuture<'static, Result<MediaPlayl
             {
                 Ok(bytes) => match parse_playlist(&bytes) {
                     Ok((_, Playlist::MasterPlaylist(playlist))) => {
-                        return choose_media_playlist(choose_urls_from_master_playlist(
-                            playlist, &url,
-                        ))
+                        return choose_media_playlist(
+                            choose_urls_from_master_playlist(playlist, &url, quality_preset),
+                            quality_preset,
+                        )
                         .await
                     }
                     Ok((_, Playlist::MediaPlaylist(mut playlist))) => {
-                        normalize_media_playlist(&mut playlist, &url);
-                        return Ok(playlist);
+                        let segment_keys = normalize_media_playlist(&mut playlist, &url);
+                        return Ok((playlist, segment_keys));
                     }
                     Err(err) => {
                         last_error = Some(Error::msg(err.to_string()));
@@ -101,21 +342,77 @@ fn choose_media_playlist(urls: Vec<Url>) -> BoxFuture<'static, Result<MediaPlayl
     .boxed()
 }
 
-fn choose_urls_from_master_playlist(mut playlist: MasterPlaylist, original_url: &Url) -> Vec<Url> {
+fn variant_bandwidth(variant: &VariantStream) -> u64 {
+    variant.bandwidth.parse().unwrap()
+}
+
+fn variant_height(variant: &VariantStream) -> Option<u32> {
+    let (_, height) = variant.resolution.as_ref()?.split_once('x')?;
+    height.parse().ok()
+}
+
+fn choose_urls_from_master_playlist(
+    mut playlist: MasterPlaylist,
+    original_url: &Url,
+    quality_preset: QualityPreset,
+) -> Vec<Url> {
     let variants = take(&mut playlist.variants);
-    let best_bandwidth = variants
-        .iter()
-        .max_by_key(|variant| variant.bandwidth.parse::<u64>().unwrap())
-        .map(|variant| variant.bandwidth.parse::<u64>().unwrap())
-        .unwrap();
-    variants
+    // Highest/Lowest keep every variant tied for the extreme bandwidth as a candidate.
+    // Nearest/MaxBandwidth instead identify a single variant by position, since two
+    // variants can share a BANDWIDTH value while differing in resolution (or cap
+    // eligibility) — re-matching on bandwidth afterwards would pull the wrong one back in.
+    let chosen_variants: Vec<VariantStream> = match quality_preset {
+        QualityPreset::Highest => {
+            let chosen_bandwidth = variants.iter().map(variant_bandwidth).max().unwrap();
+            variants
+                .into_iter()
+                .filter(|variant| variant_bandwidth(variant) == chosen_bandwidth)
+                .collect()
+        }
+        QualityPreset::Lowest => {
+            let chosen_bandwidth = variants.iter().map(variant_bandwidth).min().unwrap();
+            variants
+                .into_iter()
+                .filter(|variant| variant_bandwidth(variant) == chosen_bandwidth)
+                .collect()
+        }
+        QualityPreset::Nearest { height } => {
+            let chosen_index = variants
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, variant)| {
+                    variant_height(variant)
+                        .map(|variant_height| (variant_height as i64 - height as i64).abs())
+                        .unwrap_or(i64::MAX)
+                })
+                .map(|(index, _)| index)
+                .unwrap();
+            vec![variants.into_iter().nth(chosen_index).unwrap()]
+        }
+        QualityPreset::MaxBandwidth(cap) => {
+            let chosen_index = variants
+                .iter()
+                .enumerate()
+                .filter(|(_, variant)| variant_bandwidth(variant) <= cap)
+                .max_by_key(|(_, variant)| variant_bandwidth(variant))
+                .or_else(|| {
+                    variants
+                        .iter()
+                        .enumerate()
+                        .min_by_key(|(_, variant)| variant_bandwidth(variant))
+                })
+                .map(|(index, _)| index)
+                .unwrap();
+            vec![variants.into_iter().nth(chosen_index).unwrap()]
+        }
+    };
+    chosen_variants
         .into_iter()
-        .filter(|variant| variant.bandwidth.parse::<u64>().unwrap() >= best_bandwidth)
         .map(|variant| variant.uri)
         .map(|uri| {
             Url::parse(&uri).unwrap_or_else(|_| {
                 Url::options()
-                    .base_url(Some(&original_url))
+                    .base_url(Some(original_url))
                     .parse(&uri)
                     .unwrap()
             })
@@ -123,54 +420,206 @@ fn choose_urls_from_master_playlist(mut playlist: MasterPlaylist, original_url:
         .collect()
 }
 
-fn normalize_media_playlist(playlist: &mut MediaPlaylist, original_url: &Url) {
-    for segment in playlist.segments.iter_mut() {
-        segment.uri = Url::parse(&segment.uri)
-            .unwrap_or_else(|_| {
-                Url::options()
-                    .base_url(Some(&original_url))
-                    .parse(&segment.uri)
-                    .unwrap()
-            })
-            .to_string();
+async fn list_variants(url: &Url) -> Result<()> {
+    let bytes = HTTP_CLIENT.get(url.as_str()).send().await?.bytes().await?;
+    match parse_playlist(&bytes) {
+        Ok((_, Playlist::MasterPlaylist(playlist))) => {
+            for variant in playlist.variants.iter() {
+                println!(
+                    "bandwidth={} resolution={} codecs={}",
+                    variant.bandwidth,
+                    variant.resolution.as_deref().unwrap_or("-"),
+                    variant.codecs.as_deref().unwrap_or("-"),
+                );
+            }
+        }
+        Ok((_, Playlist::MediaPlaylist(_))) => {
+            println!("{} is already a media playlist; no variants to list", url);
+        }
+        Err(err) => return Err(Error::msg(err.to_string())),
     }
+    Ok(())
 }
 
-async fn download_ts_and_replace(dir: &Path, playlist: &mut MediaPlaylist) -> Result<()> {
+fn normalize_media_playlist(
+    playlist: &mut MediaPlaylist,
+    original_url: &Url,
+) -> Vec<Option<SegmentKey>> {
+    let media_sequence = playlist.media_sequence as u64;
+    playlist
+        .segments
+        .iter_mut()
+        .enumerate()
+        .map(|(index, segment)| {
+            let segment_key = segment.key.as_ref().and_then(|key| {
+                if key.method != "AES-128" {
+                    return None;
+                }
+                let key_uri = key.uri.as_ref()?;
+                let key_url = Url::parse(key_uri).unwrap_or_else(|_| {
+                    Url::options()
+                        .base_url(Some(original_url))
+                        .parse(key_uri)
+                        .unwrap()
+                });
+                let iv = key
+                    .iv
+                    .as_deref()
+                    .and_then(decode_hex_iv)
+                    .unwrap_or_else(|| sequence_iv(media_sequence + index as u64));
+                Some(SegmentKey { key_url, iv })
+            });
+
+            // Only drop the EXT-X-KEY tag once we've actually resolved a key to decrypt
+            // with; otherwise the rewritten playlist would claim cleartext while the
+            // segment file on disk is still ciphertext (e.g. an unsupported METHOD).
+            if segment_key.is_some() {
+                segment.key = None;
+            }
+
+            segment.uri = Url::parse(&segment.uri)
+                .unwrap_or_else(|_| {
+                    Url::options()
+                        .base_url(Some(original_url))
+                        .parse(&segment.uri)
+                        .unwrap()
+                })
+                .to_string();
+
+            segment_key
+        })
+        .collect()
+}
+
+async fn download_ts_and_replace(
+    dir: &Path,
+    playlist: &mut MediaPlaylist,
+    segment_keys: &[Option<SegmentKey>],
+) -> Result<()> {
     let semaphore = Arc::new(Semaphore::new(10));
+    let multi_progress = Arc::new(MultiProgress::new());
+    let total_progress = multi_progress.add(ProgressBar::new(playlist.segments.len() as u64));
+    total_progress.set_style(TOTAL_PROGRESS_STYLE.to_owned());
+    let manifest = Arc::new(Mutex::new(load_manifest(dir).await));
+    let key_cache = Arc::new(Mutex::new(HashMap::<String, Arc<[u8; 16]>>::new()));
+
     let tasks: Vec<_> = playlist
         .segments
         .iter()
         .enumerate()
         .map(|(id, segment)| {
             let url = segment.uri.to_owned();
+            let segment_key = segment_keys.get(id).cloned().flatten();
             let semaphore = semaphore.to_owned();
+            let multi_progress = multi_progress.to_owned();
+            let total_progress = total_progress.to_owned();
+            let manifest = manifest.to_owned();
+            let key_cache = key_cache.to_owned();
+            let dir = dir.to_path_buf();
             async move {
                 let _permit = semaphore.acquire_owned().await;
                 let mut file_path = dir.to_path_buf();
                 file_path.push(format!("{}.ts", id));
-                let ts_file = OpenOptions::new()
-                    .create(true)
-                    .write(true)
-                    .open(&file_path)
-                    .await
-                    .unwrap();
-                download_ts_to(&url, ts_file).await.unwrap();
-                (id, file_path.into_os_string().into_string().unwrap())
+
+                let already_complete = {
+                    let manifest = manifest.lock().await;
+                    match manifest.segments.get(&id) {
+                        Some(record) if record.url == url => metadata(&file_path)
+                            .await
+                            .map(|metadata| metadata.len() == record.length)
+                            .unwrap_or(false),
+                        _ => false,
+                    }
+                };
+
+                if already_complete {
+                    let segment_progress = multi_progress.add(ProgressBar::new(1));
+                    segment_progress.set_style(SEGMENT_PROGRESS_STYLE.to_owned());
+                    segment_progress.finish_with_message(format!("already complete: {}", url));
+                    multi_progress.remove(&segment_progress);
+                } else {
+                    let ts_file = OpenOptions::new()
+                        .create(true)
+                        .write(true)
+                        .truncate(false)
+                        .open(&file_path)
+                        .await
+                        .unwrap();
+
+                    let segment_progress = multi_progress.add(ProgressBar::new(0));
+                    segment_progress.set_style(SEGMENT_PROGRESS_STYLE.to_owned());
+                    let mut length = download_ts_to(&url, ts_file, &segment_progress)
+                        .await
+                        .unwrap();
+                    multi_progress.remove(&segment_progress);
+
+                    // Decrypt into a sibling temp file and only rename it over the completed
+                    // ciphertext after the manifest records the segment as done: if the
+                    // process dies before the rename, the original ciphertext is still
+                    // intact on disk and the next run simply redecrypts it, instead of
+                    // resuming a Range fetch on top of an already-decrypted file.
+                    let mut pending_rename = None;
+                    if let Some(segment_key) = &segment_key {
+                        let key = fetch_decryption_key(&key_cache, &segment_key.key_url).await?;
+                        let plaintext = decrypt_aes128_cbc(
+                            &read(&file_path).await.unwrap(),
+                            &key,
+                            &segment_key.iv,
+                        )
+                        .unwrap();
+                        length = plaintext.len() as u64;
+                        let tmp_path = PathBuf::from(format!("{}.tmp", file_path.display()));
+                        write(&tmp_path, plaintext).await.unwrap();
+                        pending_rename = Some(tmp_path);
+                    }
+
+                    {
+                        let mut manifest = manifest.lock().await;
+                        manifest.segments.insert(
+                            id,
+                            SegmentRecord {
+                                url: url.clone(),
+                                length,
+                            },
+                        );
+                        save_manifest(&dir, &manifest).await.unwrap();
+                    }
+
+                    if let Some(tmp_path) = pending_rename {
+                        rename(&tmp_path, &file_path).await.unwrap();
+                    }
+                }
+                total_progress.inc(1);
+
+                Ok((id, file_path.into_os_string().into_string().unwrap()))
             }
         })
         .collect();
 
-    for (id, url) in join_all(tasks).await.into_iter() {
-        playlist.segments.get_mut(id).unwrap().uri = url;
+    let mut failures = Vec::new();
+    for result in join_all(tasks).await.into_iter() {
+        match result {
+            Ok((id, url)) => playlist.segments.get_mut(id).unwrap().uri = url,
+            // A permanent decryption key failure fails only this segment: the other
+            // concurrently downloading segments still run to completion, and we only
+            // report the overall download as failed once they're all done.
+            Err(err) => failures.push(err),
+        }
+    }
+
+    total_progress.finish();
+
+    if let Some(err) = failures.into_iter().next() {
+        return Err(err);
     }
 
     Ok(())
 }
 
-async fn download_ts_to(url: &str, mut file: File) -> Result<()> {
+async fn download_ts_to(url: &str, mut file: File, progress: &ProgressBar) -> Result<u64> {
     const RETRIES: u8 = 10;
     let mut start = file.seek(SeekFrom::End(0)).await?;
+    progress.set_position(start);
 
     loop {
         let mut response: Option<Response> = None;
@@ -193,6 +642,9 @@ async fn download_ts_to(url: &str, mut file: File) -> Result<()> {
         let mut response =
             response.unwrap_or_else(|| panic!("Too many times to be failed to get {}", url));
         let should_read = response.content_length();
+        if let Some(should_read) = should_read {
+            progress.set_length(start + should_read);
+        }
         let mut have_read = 0;
 
         let mut retried = 0;
@@ -202,18 +654,19 @@ async fn download_ts_to(url: &str, mut file: File) -> Result<()> {
                     file.write_all(&chunk).await?;
                     start += chunk.len() as u64;
                     have_read += chunk.len() as u64;
+                    progress.set_position(start);
                     retried = 0;
                 }
                 Ok(None) => {
                     file.flush().await?;
-                    println!("Get TS: {}", url);
+                    progress.finish_with_message(format!("done: {}", url));
 
                     if let Some(should_read) = should_read {
                         if should_read != have_read {
                             eprintln!("WARNING: HTTP Get Body size doesn't match Content-Length, expected: {}, actual: {}", should_read, have_read);
                         }
                     }
-                    return Ok(());
+                    return Ok(start);
                 }
                 Err(err) => {
                     eprintln!("HTTP Get Body Error ({} / {}): {}", retried, RETRIES, err);
@@ -223,3 +676,83 @@ async fn download_ts_to(url: &str, mut file: File) -> Result<()> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn variant(uri: &str, bandwidth: u64, resolution: Option<&str>) -> VariantStream {
+        VariantStream {
+            uri: uri.to_string(),
+            bandwidth: bandwidth.to_string(),
+            resolution: resolution.map(str::to_string),
+            ..Default::default()
+        }
+    }
+
+    fn master_playlist(variants: Vec<VariantStream>) -> MasterPlaylist {
+        MasterPlaylist {
+            variants,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn variant_height_parses_the_resolution_height() {
+        let low = variant("low.m3u8", 1_000_000, Some("640x360"));
+        let high = variant("high.m3u8", 1_000_000, Some("1920x1080"));
+        assert_eq!(variant_height(&low), Some(360));
+        assert_eq!(variant_height(&high), Some(1080));
+        assert_eq!(variant_height(&variant("none.m3u8", 1_000_000, None)), None);
+    }
+
+    #[test]
+    fn nearest_picks_the_variant_by_identity_not_by_shared_bandwidth() {
+        let playlist = master_playlist(vec![
+            variant("360p.m3u8", 1_000_000, Some("640x360")),
+            variant("1080p.m3u8", 1_000_000, Some("1920x1080")),
+        ]);
+        let original_url = Url::parse("https://example.com/master.m3u8").unwrap();
+        let urls = choose_urls_from_master_playlist(
+            playlist,
+            &original_url,
+            QualityPreset::Nearest { height: 400 },
+        );
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls[0].as_str(), "https://example.com/360p.m3u8");
+    }
+
+    #[test]
+    fn max_bandwidth_picks_the_variant_by_identity_not_by_shared_bandwidth() {
+        let playlist = master_playlist(vec![
+            variant("under-cap.m3u8", 1_000_000, Some("640x360")),
+            variant("also-under-cap.m3u8", 1_000_000, Some("1920x1080")),
+        ]);
+        let original_url = Url::parse("https://example.com/master.m3u8").unwrap();
+        let urls = choose_urls_from_master_playlist(
+            playlist,
+            &original_url,
+            QualityPreset::MaxBandwidth(1_000_000),
+        );
+        assert_eq!(urls.len(), 1);
+        assert_eq!(urls[0].as_str(), "https://example.com/also-under-cap.m3u8");
+    }
+
+    #[test]
+    fn highest_and_lowest_still_return_every_tied_variant() {
+        let playlist = master_playlist(vec![
+            variant("360p.m3u8", 1_000_000, Some("640x360")),
+            variant("1080p.m3u8", 1_000_000, Some("1920x1080")),
+        ]);
+        let original_url = Url::parse("https://example.com/master.m3u8").unwrap();
+        let urls = choose_urls_from_master_playlist(
+            playlist.clone(),
+            &original_url,
+            QualityPreset::Highest,
+        );
+        assert_eq!(urls.len(), 2);
+        let urls =
+            choose_urls_from_master_playlist(playlist, &original_url, QualityPreset::Lowest);
+        assert_eq!(urls.len(), 2);
+    }
+}